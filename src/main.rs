@@ -1,46 +1,216 @@
 use std::env;
-use std::io;
-use std::ops::Not;
 use std::process;
 
+mod search;
+
 struct Matcher {
     fragments: Vec<Match>,
+    engine: Engine,
+    program: Vec<Inst>,
+    capture_count: usize,
+    case_insensitive: bool,
+}
+
+type PatternChars<'a> = std::iter::Peekable<std::str::Chars<'a>>;
+
+/// A line being matched against, indexed by char rather than by byte so the
+/// backtracking engine's `char_index` bookkeeping is correct on multi-byte
+/// input. `boundaries[i]` is the byte offset of char `i`, with one extra
+/// trailing entry for the end of the string.
+struct Input<'a> {
+    text: &'a str,
+    boundaries: Vec<usize>,
+}
+
+impl<'a> Input<'a> {
+    fn new(text: &'a str) -> Self {
+        let mut boundaries: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+        boundaries.push(text.len());
+
+        Self { text, boundaries }
+    }
+
+    fn len(&self) -> usize {
+        self.boundaries.len() - 1
+    }
+
+    fn char_at(&self, char_index: usize) -> Option<char> {
+        let byte_index = *self.boundaries.get(char_index)?;
+        self.text[byte_index..].chars().next()
+    }
+
+    fn slice(&self, start: usize, end: usize) -> &'a str {
+        &self.text[self.boundaries[start]..self.boundaries[end]]
+    }
+}
+
+/// Which execution strategy `Matcher::match` uses. The NFA simulation runs in
+/// O(n*m) and can't blow up the way backtracking can on patterns like
+/// `(a+)+b`, so it's the default whenever the pattern contains an unbounded
+/// quantifier and no backreference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Engine {
+    Backtracking,
+    Nfa,
 }
 
 impl Matcher {
-    fn from_pattern(pattern: &str) -> Self {
-        let fragments = Self::parse_pattern(pattern);
+    /// `case_insensitive` is `-i`, forcing every match to fold case.
+    /// `smart_case` is ripgrep's `-S`: it only takes effect when `-i` wasn't
+    /// given, and even then only folds case if the pattern itself has no
+    /// uppercase letters (on the assumption an uppercase letter was typed
+    /// deliberately). Neither flag is implied by the other - plain `grep -E`
+    /// semantics (fully case-sensitive) remain the default.
+    fn from_pattern(pattern: &str, case_insensitive: bool, smart_case: bool) -> Self {
+        let mut capture_count = 0;
+        let fragments = Self::parse_alternation(&mut pattern.chars().peekable(), &mut capture_count);
+        let engine = if Self::requires_backtracking(&fragments) {
+            Engine::Backtracking
+        } else if Self::has_unbounded_quantifier(&fragments) {
+            Engine::Nfa
+        } else {
+            Engine::Backtracking
+        };
+        let program = Self::compile(&fragments);
+        let case_insensitive =
+            case_insensitive || (smart_case && Self::is_smart_case(pattern));
+
+        Self {
+            fragments,
+            engine,
+            program,
+            capture_count,
+            case_insensitive,
+        }
+    }
+
+    fn is_smart_case(pattern: &str) -> bool {
+        !pattern.chars().any(|c| c.is_ascii_uppercase())
+    }
+
+    /// Which engine `r#match` currently runs on - `Backtracking` unless the
+    /// pattern has an unbounded quantifier and doesn't need backtracking
+    /// (see `requires_backtracking`), in which case it's `Nfa`.
+    fn engine(&self) -> Engine {
+        self.engine
+    }
+
+    /// Overrides the automatically-selected engine, e.g. to force
+    /// backtracking in a test that wants to pin down which engine a
+    /// particular assertion is exercising. Both the fragment tree and the
+    /// NFA program are always built in `from_pattern` regardless of which
+    /// one ends up driving `r#match`, so switching is just a flag flip.
+    #[cfg(test)]
+    fn with_engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    fn has_unbounded_quantifier(fragments: &[Match]) -> bool {
+        fragments.iter().any(|fragment| match fragment {
+            Match::OneOfMore(_) | Match::ZeroOrMore(_) => true,
+            Match::ZeroOrOne(inner) => {
+                Self::has_unbounded_quantifier(std::slice::from_ref(inner.as_ref()))
+            }
+            Match::PositiveGroup(group) | Match::NegativeGroup(group) => {
+                Self::has_unbounded_quantifier(group)
+            }
+            Match::Group(_, inner) => Self::has_unbounded_quantifier(inner),
+            Match::Alternation(branches) => branches.iter().any(|b| Self::has_unbounded_quantifier(b)),
+            Match::Literal(_)
+            | Match::Class(_)
+            | Match::AnyChar
+            | Match::StartOfLine
+            | Match::EndOfLine
+            | Match::GroupEnd(_, _)
+            | Match::Backreference(_)
+            | Match::Range(_, _) => false,
+        })
+    }
+
+    /// Only a backreference truly needs the recursive matcher - it looks
+    /// back at text a capture group recorded, which the NFA has no table to
+    /// hold. Groups and alternation are matched transparently (their submatch
+    /// boundaries aren't tracked) by `compile_fragment`, so on their own they
+    /// don't force backtracking.
+    fn requires_backtracking(fragments: &[Match]) -> bool {
+        fragments.iter().any(|fragment| match fragment {
+            Match::Backreference(_) => true,
+            Match::OneOfMore(inner) | Match::ZeroOrMore(inner) | Match::ZeroOrOne(inner) => {
+                Self::requires_backtracking(std::slice::from_ref(inner.as_ref()))
+            }
+            Match::PositiveGroup(group) | Match::NegativeGroup(group) => {
+                Self::requires_backtracking(group)
+            }
+            Match::Group(_, inner) => Self::requires_backtracking(inner),
+            Match::Alternation(branches) => {
+                branches.iter().any(|branch| Self::requires_backtracking(branch))
+            }
+            Match::Literal(_)
+            | Match::Class(_)
+            | Match::AnyChar
+            | Match::StartOfLine
+            | Match::EndOfLine
+            | Match::GroupEnd(_, _)
+            | Match::Range(_, _) => false,
+        })
+    }
+
+    /// Parse one or more `|`-separated sequences, the top level of
+    /// `from_pattern` and the body of every `(...)` group.
+    fn parse_alternation(chars: &mut PatternChars, capture_count: &mut usize) -> Vec<Match> {
+        let mut branches = vec![Self::parse_sequence(chars, capture_count)];
+
+        while chars.peek() == Some(&'|') {
+            chars.next();
+            branches.push(Self::parse_sequence(chars, capture_count));
+        }
 
-        Self { fragments }
+        if branches.len() == 1 {
+            branches.pop().unwrap()
+        } else {
+            vec![Match::Alternation(branches)]
+        }
     }
 
-    fn parse_pattern(pattern: &str) -> Vec<Match> {
+    fn parse_sequence(chars: &mut PatternChars, capture_count: &mut usize) -> Vec<Match> {
         let mut fragments = Vec::new();
-        let mut chars = pattern.chars();
 
-        while let Some(c) = chars.next() {
+        while let Some(&c) = chars.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            chars.next();
+
             match c {
                 '\\' => {
-                    Self::parse_character_class(&mut chars, &mut fragments);
+                    Self::parse_character_class(chars, &mut fragments);
                 }
                 '[' => {
-                    Self::parse_positive_character_group(&mut chars, &mut fragments);
+                    Self::parse_positive_character_group(chars, &mut fragments);
                 }
-                // TODO: Maybe these have to be first/last and we should check that?
-                '^' => {
-                    let next_char = chars.next().unwrap().to_string();
-                    fragments.push(Match::StartOfLine(Box::new(
-                        Self::parse_pattern(&next_char).pop().unwrap(),
-                    )));
-                }
-                '$' => {
-                    let previous_fragment = fragments.pop().unwrap();
-                    fragments.push(Match::EndOfLine(Box::new(previous_fragment)));
+                '(' => {
+                    *capture_count += 1;
+                    let index = *capture_count;
+                    let inner = Self::parse_alternation(chars, capture_count);
+                    chars.next(); // consume the closing ')'
+                    fragments.push(Match::Group(index, inner));
                 }
+                // TODO: Maybe these have to be first/last and we should check that?
+                // Pushed as a bare zero-width marker (not wrapped around a
+                // neighboring fragment) so whatever comes before/after it -
+                // including a quantifier, group or alternation - is parsed
+                // and matched like any other fragment in the sequence.
+                '^' => fragments.push(Match::StartOfLine),
+                '$' => fragments.push(Match::EndOfLine),
                 '+' => {
                     let previous_fragment = fragments.pop().unwrap();
                     fragments.push(Match::OneOfMore(Box::new(previous_fragment)));
                 }
+                '*' => {
+                    let previous_fragment = fragments.pop().unwrap();
+                    fragments.push(Match::ZeroOrMore(Box::new(previous_fragment)));
+                }
                 '?' => {
                     let previous_fragment = fragments.pop().unwrap();
                     fragments.push(Match::ZeroOrOne(Box::new(previous_fragment)));
@@ -55,21 +225,25 @@ impl Matcher {
         fragments
     }
 
-    fn parse_character_class(chars: &mut std::str::Chars, fragments: &mut Vec<Match>) {
+    fn parse_character_class(chars: &mut PatternChars, fragments: &mut Vec<Match>) {
         match chars.next() {
             Some('d') => fragments.push(Match::Class(Class::Digit)),
             Some('w') => fragments.push(Match::Class(Class::Word)),
-            Some('\\') => fragments.push(Match::Literal('\\'.to_string())),
+            Some('s') => fragments.push(Match::Class(Class::Whitespace)),
+            Some(c @ ('\\' | ']' | '-' | '^')) => fragments.push(Match::Literal(c.to_string())),
+            Some(c) if c.is_ascii_digit() && c != '0' => {
+                fragments.push(Match::Backreference(c.to_digit(10).unwrap() as usize))
+            }
             Some(c) => todo!("Handle character class: {}", c),
             None => panic!("Expected character after '\\'"),
         }
     }
 
-    fn parse_positive_character_group(chars: &mut std::str::Chars, fragments: &mut Vec<Match>) {
+    fn parse_positive_character_group(chars: &mut PatternChars, fragments: &mut Vec<Match>) {
         let mut group = Vec::new();
         let mut group_negative = false;
 
-        for c in chars.by_ref() {
+        while let Some(c) = chars.next() {
             match c {
                 // TODO: This can only be the first character in the group, should be an error otherwise
                 '^' => {
@@ -83,219 +257,663 @@ impl Matcher {
                     }
                     return;
                 }
-                // TODO: not gauranteed to be a literal, should use the parse function, but is regex recursive?
+                '\\' => Self::parse_character_class(chars, &mut group),
+                // `a-z`: a range, unless the `-` is trailing (`a-]` means the
+                // literals `a` and `-`).
+                c if chars.peek() == Some(&'-') => {
+                    let mut lookahead = chars.clone();
+                    lookahead.next();
+
+                    match lookahead.peek() {
+                        Some(&end) if end != ']' => {
+                            chars.next();
+                            chars.next();
+                            group.push(Match::Range(c, end));
+                        }
+                        _ => group.push(Match::Literal(c.to_string())),
+                    }
+                }
                 c => group.push(Match::Literal(c.to_string())),
             }
         }
     }
 
     fn r#match(&self, input_line: &str) -> bool {
-        let mut char_index = 0;
-        let mut fragments = self.fragments.iter();
-        let mut current_fragment = fragments.next();
+        match self.engine() {
+            Engine::Backtracking => self.match_backtracking(input_line),
+            Engine::Nfa => self.match_nfa(input_line),
+        }
+    }
 
-        loop {
-            // We are out of fragments, so the pattern has matched
-            if current_fragment.is_none() {
-                return true;
-            }
+    /// Unanchored search: try `match_here` at every start position unless the
+    /// pattern is anchored to the start of the line, in which case only
+    /// position 0 is a candidate.
+    fn match_backtracking(&self, input_line: &str) -> bool {
+        self.captures(input_line).is_some()
+    }
 
-            // We are out of string, but still have fragments, so we didn't match
-            if char_index >= input_line.len() {
-                return false;
+    /// Run the backtracking engine and, on a successful match, return the
+    /// matched slices: index 0 is the whole match, followed by one entry per
+    /// capture group in the order its `(` appeared. Positions throughout the
+    /// engine are char indices (via `input.boundaries`), not byte offsets, so
+    /// multi-byte input is matched correctly.
+    pub fn captures<'a>(&self, input_line: &'a str) -> Option<Vec<Option<&'a str>>> {
+        let input = Input::new(input_line);
+        let anchored = matches!(self.fragments.first(), Some(Match::StartOfLine));
+        let starts: Box<dyn Iterator<Item = usize>> = if anchored {
+            Box::new(0..=0)
+        } else {
+            Box::new(0..=input.len())
+        };
+
+        for start in starts {
+            let mut captures = vec![None; self.capture_count + 1];
+            if let Some(end) = self.match_here(&self.fragments, &input, start, &mut captures) {
+                captures[0] = Some((start, end));
+                return Some(
+                    captures
+                        .into_iter()
+                        .map(|c| c.map(|(s, e)| input.slice(s, e)))
+                        .collect(),
+                );
             }
+        }
 
-            let fragment = current_fragment.unwrap();
+        None
+    }
 
-            match fragment.r#match(input_line, &char_index) {
-                MatchResult::Match(match_length) => {
-                    // The fragment matched, so we can get the next fragment
-                    current_fragment = fragments.next();
-                    char_index += match_length;
-                }
-                MatchResult::NoMatch => {
-                    char_index += 1;
+    /// Try to match `fragments[0]` at `char_index`, then recurse on the rest
+    /// of the fragment list for every length `fragments[0]` could consume.
+    /// Backtracks by trying the next-best length when a later fragment fails,
+    /// which is what lets a greedy quantifier give characters back.
+    fn match_here(
+        &self,
+        fragments: &[Match],
+        input: &Input,
+        char_index: usize,
+        captures: &mut Vec<Option<(usize, usize)>>,
+    ) -> Option<usize> {
+        let (fragment, rest) = match fragments.split_first() {
+            None => return Some(char_index),
+            Some(pair) => pair,
+        };
+
+        match fragment {
+            // `a+` is `a` followed by `a*` - splicing the expansion into the
+            // fragment list (rather than matching `inner` against an empty
+            // rest) lets `inner`'s own alternation/nested quantifiers give
+            // characters back to `rest` on backtrack, same as a `Group` does.
+            Match::OneOfMore(inner) => {
+                let mut combined = vec![(**inner).clone(), Match::ZeroOrMore(inner.clone())];
+                combined.extend_from_slice(rest);
+                self.match_here(&combined, input, char_index, captures)
+            }
+            // `a*` is greedily `a` followed by `a*` again, falling back to
+            // skipping `a` entirely. Repeating again is skipped outright when
+            // `inner` can match empty, since that would otherwise recurse
+            // forever; such an `inner` is then tried at most once, so
+            // `^(a?b?)*$` does not match `abab` on this engine.
+            Match::ZeroOrMore(inner) => {
+                let mut combined = vec![(**inner).clone()];
+                if !Self::is_nullable(inner) {
+                    combined.push(Match::ZeroOrMore(inner.clone()));
                 }
+                combined.extend_from_slice(rest);
+
+                self.match_here(&combined, input, char_index, captures)
+                    .or_else(|| self.match_here(rest, input, char_index, captures))
+            }
+            // `a?` is `a` followed by `rest`, falling back to `rest` alone.
+            Match::ZeroOrOne(inner) => {
+                let mut combined = vec![(**inner).clone()];
+                combined.extend_from_slice(rest);
+
+                self.match_here(&combined, input, char_index, captures)
+                    .or_else(|| self.match_here(rest, input, char_index, captures))
+            }
+            // Match the group's fragments, then record its span and fall
+            // through into `rest` - splicing a `GroupEnd` marker in between
+            // lets the group's own backtracking still retry against `rest`.
+            Match::Group(index, inner) => {
+                let mut combined = inner.clone();
+                combined.push(Match::GroupEnd(*index, char_index));
+                combined.extend_from_slice(rest);
+                self.match_here(&combined, input, char_index, captures)
             }
+            Match::Alternation(branches) => branches.iter().find_map(|branch| {
+                let mut combined = branch.clone();
+                combined.extend_from_slice(rest);
+                self.match_here(&combined, input, char_index, captures)
+            }),
+            _ => match self.match_fragment(fragment, input, char_index, captures) {
+                Some(len) => self.match_here(rest, input, char_index + len, captures),
+                None => None,
+            },
         }
     }
-}
 
-#[derive(Debug)]
-enum Match {
-    Literal(String),
-    Class(Class),
-    PositiveGroup(Vec<Match>),
-    NegativeGroup(Vec<Match>),
-    StartOfLine(Box<Match>),
-    EndOfLine(Box<Match>),
-    OneOfMore(Box<Match>),
-    ZeroOrOne(Box<Match>),
-    AnyChar,
-}
+    /// Whether `fragment` can match the empty string, independent of any
+    /// particular input - used to stop a `ZeroOrMore` from re-expanding
+    /// forever when its body never has to consume a character.
+    fn is_nullable(fragment: &Match) -> bool {
+        match fragment {
+            Match::Literal(s) => s.is_empty(),
+            Match::Class(_) | Match::AnyChar | Match::Range(_, _) => false,
+            Match::PositiveGroup(_) | Match::NegativeGroup(_) => false,
+            Match::OneOfMore(inner) => Self::is_nullable(inner),
+            Match::StartOfLine
+            | Match::EndOfLine
+            | Match::ZeroOrMore(_)
+            | Match::ZeroOrOne(_)
+            | Match::GroupEnd(_, _) => true,
+            Match::Group(_, inner) => inner.iter().all(Self::is_nullable),
+            Match::Alternation(branches) => branches
+                .iter()
+                .any(|branch| branch.iter().all(Self::is_nullable)),
+            // Not statically knowable until match time; treat as nullable so
+            // we never risk looping instead of potentially under-matching.
+            Match::Backreference(_) => true,
+        }
+    }
 
-enum MatchResult {
-    Match(usize),
-    NoMatch,
-}
+    /// Match a single, non-quantifier, non-group fragment at `char_index`,
+    /// returning how many chars it consumed.
+    fn match_fragment(
+        &self,
+        fragment: &Match,
+        input: &Input,
+        char_index: usize,
+        captures: &mut Vec<Option<(usize, usize)>>,
+    ) -> Option<usize> {
+        match fragment {
+            Match::Literal(literal) => {
+                let literal_length = literal.chars().count();
 
-impl Not for MatchResult {
-    type Output = Self;
+                if char_index + literal_length > input.len() {
+                    return None;
+                }
+
+                let candidate = input.slice(char_index, char_index + literal_length);
+                self.str_eq(candidate, literal).then_some(literal_length)
+            }
+            Match::Class(class) => {
+                let c = input.char_at(char_index)?;
+
+                Self::class_matches(class, c).then_some(1)
+            }
+            Match::Range(start, end) => {
+                let c = input.char_at(char_index)?;
+
+                self.in_range(*start, *end, c).then_some(1)
+            }
+            Match::PositiveGroup(group_fragments) => {
+                input.char_at(char_index)?;
+
+                group_fragments
+                    .iter()
+                    .any(|fragment| {
+                        self.match_fragment(fragment, input, char_index, captures).is_some()
+                    })
+                    .then_some(1)
+            }
+            Match::NegativeGroup(group_fragments) => {
+                input.char_at(char_index)?;
+
+                group_fragments
+                    .iter()
+                    .all(|fragment| {
+                        self.match_fragment(fragment, input, char_index, captures).is_none()
+                    })
+                    .then_some(1)
+            }
+            Match::StartOfLine => (char_index == 0).then_some(0),
+            Match::EndOfLine => (char_index == input.len()).then_some(0),
+            Match::AnyChar => input.char_at(char_index).map(|_| 1),
+            Match::GroupEnd(index, start) => {
+                captures[*index] = Some((*start, char_index));
+                Some(0)
+            }
+            Match::Backreference(index) => {
+                let (start, end) = captures.get(*index).copied().flatten()?;
+                let text = input.slice(start, end);
+                let text_length = end - start;
+
+                if char_index + text_length > input.len() {
+                    return None;
+                }
 
-    fn not(self) -> Self::Output {
-        match self {
-            MatchResult::Match(_) => MatchResult::NoMatch,
-            MatchResult::NoMatch => MatchResult::Match(0),
+                let candidate = input.slice(char_index, char_index + text_length);
+                self.str_eq(candidate, text).then_some(text_length)
+            }
+            Match::OneOfMore(_) | Match::ZeroOrMore(_) | Match::ZeroOrOne(_) => {
+                unreachable!("quantifiers are handled directly in match_here")
+            }
+            Match::Group(_, _) | Match::Alternation(_) => {
+                unreachable!("groups and alternation are handled directly in match_here")
+            }
         }
     }
-}
 
-impl From<bool> for MatchResult {
-    fn from(b: bool) -> Self {
-        if b {
-            MatchResult::Match(1)
-        } else {
-            MatchResult::NoMatch
-        }
+    /// Compile the fragment tree into a flat instruction program, wrapping it
+    /// in a `Save` marking each end of the whole-match span (the NFA only
+    /// reports whether a match exists, so neither `Save` carries a slot index).
+    fn compile(fragments: &[Match]) -> Vec<Inst> {
+        let mut program = vec![Inst::Save];
+        Self::compile_fragments(fragments, &mut program);
+        program.push(Inst::Save);
+        program.push(Inst::Match);
+        program
     }
-}
 
-impl From<MatchResult> for bool {
-    fn from(m: MatchResult) -> Self {
-        match m {
-            MatchResult::Match(_) => true,
-            MatchResult::NoMatch => false,
+    fn compile_fragments(fragments: &[Match], program: &mut Vec<Inst>) {
+        for fragment in fragments {
+            Self::compile_fragment(fragment, program);
         }
     }
-}
 
-impl Match {
-    fn r#match(&self, input_line: &str, char_index: &usize) -> MatchResult {
-        match self {
+    fn compile_fragment(fragment: &Match, program: &mut Vec<Inst>) {
+        match fragment {
             Match::Literal(literal) => {
-                let literal_length = literal.len();
-
-                if input_line.len() < literal_length {
-                    return MatchResult::NoMatch;
+                for c in literal.chars() {
+                    program.push(Inst::Char(c));
                 }
+            }
+            Match::Class(class) => program.push(Inst::Class(class.clone())),
+            Match::Range(start, end) => program.push(Inst::Range(*start, *end)),
+            Match::PositiveGroup(group) => program.push(Inst::Group(group.clone(), false)),
+            Match::NegativeGroup(group) => program.push(Inst::Group(group.clone(), true)),
+            Match::StartOfLine => program.push(Inst::StartAnchor),
+            Match::EndOfLine => program.push(Inst::EndAnchor),
+            Match::AnyChar => program.push(Inst::AnyChar),
+            // L: <frag> Split(L, out)
+            Match::OneOfMore(inner) => {
+                let l = program.len();
+                Self::compile_fragment(inner, program);
+                let out = program.len() + 1;
+                program.push(Inst::Split(l, out));
+            }
+            // split: Split(body, out) body: <frag> Jump(split) out:
+            Match::ZeroOrMore(inner) => {
+                let split = program.len();
+                program.push(Inst::Split(0, 0));
+                let body = program.len();
+                Self::compile_fragment(inner, program);
+                program.push(Inst::Jump(split));
+                let out = program.len();
+                program[split] = Inst::Split(body, out);
+            }
+            // split: Split(body, out) body: <frag> out:
+            Match::ZeroOrOne(inner) => {
+                let split = program.len();
+                program.push(Inst::Split(0, 0));
+                let body = program.len();
+                Self::compile_fragment(inner, program);
+                let out = program.len();
+                program[split] = Inst::Split(body, out);
+            }
+            // Groups are transparent to the NFA: it has no way to record
+            // submatches, so it just inlines the group's fragments.
+            Match::Group(_, inner) => Self::compile_fragments(inner, program),
+            // branch0: Split(body0, next0) body0: <branch0> Jump(out)
+            // next0: Split(body1, next1) body1: <branch1> Jump(out) ... out:
+            Match::Alternation(branches) => {
+                let mut jumps = Vec::new();
+
+                for (i, branch) in branches.iter().enumerate() {
+                    if i + 1 == branches.len() {
+                        Self::compile_fragments(branch, program);
+                        continue;
+                    }
 
-                let input_line_fragment = &input_line[*char_index..*char_index + literal_length];
-
-                if input_line_fragment != *literal {
-                    return MatchResult::NoMatch;
+                    let split = program.len();
+                    program.push(Inst::Split(0, 0));
+                    let body = program.len();
+                    Self::compile_fragments(branch, program);
+                    jumps.push(program.len());
+                    program.push(Inst::Jump(0));
+                    let next = program.len();
+                    program[split] = Inst::Split(body, next);
                 }
 
-                MatchResult::Match(literal_length)
-            }
-            Match::Class(class) => match class {
-                // TODO: Very similar code, should be able to generalize with a high order function
-                Class::Digit => input_line[*char_index..]
-                    .chars()
-                    .next()
-                    .unwrap()
-                    .is_ascii_digit()
-                    .into(),
-                Class::Word => input_line[*char_index..]
-                    .chars()
-                    .next()
-                    .unwrap()
-                    .is_ascii_alphanumeric()
-                    .into(),
-            },
-            Match::PositiveGroup(group_fragments) => group_fragments
-                .iter()
-                .any(|fragment| fragment.r#match(input_line, char_index).into())
-                .into(),
-            Match::NegativeGroup(group_fragments) => group_fragments
-                .iter()
-                .all(|fragment| (!fragment.r#match(input_line, char_index)).into())
-                .into(),
-            Match::StartOfLine(fragment) => {
-                let result = fragment.r#match(input_line, char_index);
-                match result {
-                    MatchResult::Match(_) => {
-                        if *char_index == 0 {
-                            result
-                        } else {
-                            MatchResult::NoMatch
-                        }
-                    }
-                    MatchResult::NoMatch => result,
+                let out = program.len();
+                for jump in jumps {
+                    program[jump] = Inst::Jump(out);
                 }
             }
-            Match::EndOfLine(fragment) => {
-                let result = fragment.r#match(input_line, char_index);
-                match result {
-                    MatchResult::Match(match_length) => {
-                        if *char_index + match_length == input_line.len() {
-                            result
+            Match::GroupEnd(_, _) => {
+                unreachable!("GroupEnd is only synthesized by the backtracking engine")
+            }
+            // The NFA has no capture table to look back into, so this
+            // compiles to an instruction that can never advance.
+            // `requires_backtracking` ensures patterns with a backreference
+            // always run on the backtracking engine instead.
+            Match::Backreference(_) => program.push(Inst::Backreference),
+        }
+    }
+
+    /// Thompson NFA simulation (Pike's VM): advance the whole set of active
+    /// threads one input character at a time, following `Split`/`Jump`/`Save`
+    /// epsilon-closures immediately and stepping consuming instructions into
+    /// `nlist`. Threads are deduped by pc with a generation stamp so each
+    /// instruction is visited at most once per input position, which is what
+    /// keeps this O(n*m) instead of exponential.
+    fn match_nfa(&self, input_line: &str) -> bool {
+        let chars: Vec<char> = input_line.chars().collect();
+        let anchored = matches!(self.program.get(1), Some(Inst::StartAnchor));
+
+        let mut seen = vec![usize::MAX; self.program.len()];
+        let mut generation = 0;
+        let mut clist = Vec::new();
+        let mut nlist = Vec::new();
+
+        generation += 1;
+        Self::add_thread(&self.program, 0, 0, chars.len(), &mut clist, &mut seen, generation);
+
+        for i in 0..=chars.len() {
+            if clist.iter().any(|&pc| matches!(self.program[pc], Inst::Match)) {
+                return true;
+            }
+
+            if i == chars.len() {
+                break;
+            }
+
+            let c = chars[i];
+            generation += 1;
+            nlist.clear();
+
+            for &pc in &clist {
+                let advances = match &self.program[pc] {
+                    Inst::Char(expected) => {
+                        if self.case_insensitive {
+                            expected.eq_ignore_ascii_case(&c)
                         } else {
-                            MatchResult::NoMatch
+                            *expected == c
                         }
                     }
-                    MatchResult::NoMatch => result,
+                    Inst::AnyChar => true,
+                    Inst::Class(class) => Self::class_matches(class, c),
+                    Inst::Range(start, end) => self.in_range(*start, *end, c),
+                    Inst::Group(members, negate) => {
+                        members
+                            .iter()
+                            .any(|m| self.group_member_matches(m, c))
+                            != *negate
+                    }
+                    _ => false,
+                };
+
+                if advances {
+                    Self::add_thread(&self.program, pc + 1, i + 1, chars.len(), &mut nlist, &mut seen, generation);
                 }
             }
-            Match::OneOfMore(fragment) => {
-                let mut match_length = 0;
 
-                loop {
-                    let new_index = *char_index + match_length;
-                    let result = fragment.r#match(input_line, &new_index);
+            clist.clone_from(&nlist);
 
-                    match result {
-                        MatchResult::Match(fragment_match_length) => {
-                            match_length += fragment_match_length;
-                        }
-                        MatchResult::NoMatch => {
-                            if match_length == 0 {
-                                return MatchResult::NoMatch;
-                            } else {
-                                return MatchResult::Match(match_length);
-                            }
-                        }
-                    }
-                }
+            if !anchored {
+                Self::add_thread(&self.program, 0, i + 1, chars.len(), &mut clist, &mut seen, generation);
             }
-            Match::ZeroOrOne(fragment) => {
-                let result = fragment.r#match(input_line, char_index);
+        }
+
+        false
+    }
+
+    /// Follow the epsilon-closure from `pc`, pushing every consuming
+    /// instruction reached onto `list` and marking each visited pc with
+    /// `generation` so it is only ever added once per input position.
+    fn add_thread(
+        program: &[Inst],
+        pc: usize,
+        char_index: usize,
+        input_len: usize,
+        list: &mut Vec<usize>,
+        seen: &mut [usize],
+        generation: usize,
+    ) {
+        if seen[pc] == generation {
+            return;
+        }
+        seen[pc] = generation;
 
-                match result {
-                    MatchResult::Match(match_length) => MatchResult::Match(match_length),
-                    MatchResult::NoMatch => MatchResult::Match(0),
+        match &program[pc] {
+            Inst::Jump(target) => {
+                Self::add_thread(program, *target, char_index, input_len, list, seen, generation)
+            }
+            Inst::Split(a, b) => {
+                Self::add_thread(program, *a, char_index, input_len, list, seen, generation);
+                Self::add_thread(program, *b, char_index, input_len, list, seen, generation);
+            }
+            Inst::Save => {
+                Self::add_thread(program, pc + 1, char_index, input_len, list, seen, generation)
+            }
+            Inst::StartAnchor => {
+                if char_index == 0 {
+                    Self::add_thread(program, pc + 1, char_index, input_len, list, seen, generation);
                 }
             }
-            Match::AnyChar => MatchResult::Match(1),
+            Inst::EndAnchor => {
+                if char_index == input_len {
+                    Self::add_thread(program, pc + 1, char_index, input_len, list, seen, generation);
+                }
+            }
+            _ => list.push(pc),
         }
     }
+
+    /// Compare two strings honoring `self.case_insensitive`.
+    fn str_eq(&self, a: &str, b: &str) -> bool {
+        if self.case_insensitive {
+            a.chars().count() == b.chars().count()
+                && a.chars().zip(b.chars()).all(|(x, y)| x.eq_ignore_ascii_case(&y))
+        } else {
+            a == b
+        }
+    }
+
+    /// Whether `c` falls in `start..=end`, honoring `self.case_insensitive` by
+    /// trying both the lower- and upper-case forms of `c`.
+    fn in_range(&self, start: char, end: char, c: char) -> bool {
+        if self.case_insensitive {
+            (start..=end).contains(&c.to_ascii_lowercase()) || (start..=end).contains(&c.to_ascii_uppercase())
+        } else {
+            (start..=end).contains(&c)
+        }
+    }
+
+    fn class_matches(class: &Class, c: char) -> bool {
+        match class {
+            Class::Digit => c.is_ascii_digit(),
+            Class::Word => c.is_ascii_alphanumeric() || c == '_',
+            Class::Whitespace => c.is_ascii_whitespace(),
+        }
+    }
+
+    fn group_member_matches(&self, member: &Match, c: char) -> bool {
+        match member {
+            Match::Literal(literal) => match literal.chars().next() {
+                Some(expected) if self.case_insensitive => expected.eq_ignore_ascii_case(&c),
+                Some(expected) => expected == c,
+                None => false,
+            },
+            Match::Class(class) => Self::class_matches(class, c),
+            Match::Range(start, end) => self.in_range(*start, *end, c),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Match {
+    Literal(String),
+    Class(Class),
+    PositiveGroup(Vec<Match>),
+    NegativeGroup(Vec<Match>),
+    /// `^`: a zero-width assertion that the match is at the start of the
+    /// line, spliced into the fragment list like `GroupEnd` rather than
+    /// wrapping the fragment that follows it, so it composes with whatever
+    /// quantifier/group/alternation comes next.
+    StartOfLine,
+    /// `$`: the same assertion for the end of the line.
+    EndOfLine,
+    OneOfMore(Box<Match>),
+    ZeroOrMore(Box<Match>),
+    ZeroOrOne(Box<Match>),
+    AnyChar,
+    /// A `(...)` capture group: its index (1-based, in the order `(` appears)
+    /// and the fragments it contains.
+    Group(usize, Vec<Match>),
+    /// A top-level `|` split within the current group: the fragment lists of
+    /// each alternative.
+    Alternation(Vec<Vec<Match>>),
+    /// Synthesized only by `Matcher::match_here` when descending into a
+    /// `Group`, never produced by the parser: marks where to record that
+    /// group's capture span once its fragments have matched.
+    GroupEnd(usize, usize),
+    /// `\1`-`\9`: match the exact text previously captured by that group.
+    Backreference(usize),
+    /// `a-z` inside a `[...]` group: matches any char in the inclusive range.
+    Range(char, char),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum Class {
     Digit,
     Word,
+    Whitespace,
 }
 
-fn match_pattern(input_line: &str, pattern: &str) -> bool {
-    let matcher = Matcher::from_pattern(pattern);
-    matcher.r#match(input_line)
+/// A flat instruction for the Thompson NFA simulation in `Matcher::match_nfa`.
+#[derive(Debug, Clone)]
+enum Inst {
+    Char(char),
+    AnyChar,
+    Class(Class),
+    Range(char, char),
+    Group(Vec<Match>, bool),
+    StartAnchor,
+    EndAnchor,
+    Split(usize, usize),
+    Jump(usize),
+    Save,
+    Match,
+    /// Never advances; see the comment on `Match::Backreference` in
+    /// `compile_fragment`.
+    Backreference,
 }
 
-// Usage: echo <input_text> | your_grep.sh -E <pattern>
+// Usage: your_grep.sh -E <pattern> [-r] [-n] [-v] [-c] [-l] [-i] [-S] [file ...]
+//        echo <input_text> | your_grep.sh -E <pattern>
 fn main() {
-    if env::args().nth(1).unwrap() != "-E" {
-        println!("Expected first argument to be '-E'");
-        process::exit(1);
+    let mut options = search::Options::default();
+    let mut pattern = None;
+    let mut paths = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-E" => pattern = Some(args.next().expect("Expected a pattern after '-E'")),
+            "-r" => options.recursive = true,
+            "-n" => options.line_numbers = true,
+            "-v" => options.invert = true,
+            "-c" => options.count_only = true,
+            "-l" => options.list_files = true,
+            "-i" => options.case_insensitive = true,
+            "-S" => options.smart_case = true,
+            _ => paths.push(arg),
+        }
+    }
+
+    let pattern = pattern.expect("Expected first argument to be '-E'");
+
+    match search::run(&pattern, &paths, &options) {
+        Ok(true) => process::exit(0),
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            eprintln!("{err}");
+            process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Engine, Matcher};
+
+    fn matches(pattern: &str, input: &str) -> bool {
+        Matcher::from_pattern(pattern, false, false).r#match(input)
     }
 
-    let pattern = env::args().nth(2).unwrap();
-    let mut input_line = String::new();
+    #[test]
+    fn literal_and_anchors() {
+        assert!(matches("cat", "a cat sat"));
+        assert!(!matches("cat", "a dog sat"));
+        assert!(matches("^cat", "cat sat"));
+        assert!(!matches("^cat", "a cat sat"));
+        assert!(matches("sat$", "a cat sat"));
+        assert!(!matches("sat$", "a cat sat down"));
+    }
 
-    io::stdin().read_line(&mut input_line).unwrap();
+    #[test]
+    fn quantified_group_backtracks_into_what_follows() {
+        // The group's own `a+` must be able to give a character back to the
+        // trailing literal `ab` instead of always consuming every `a`.
+        assert!(matches("(a+)+ab", "aaaab"));
+        assert!(!matches("(a+)+ab", "aaaa"));
+    }
+
+    #[test]
+    fn backreference_requires_the_same_captured_text() {
+        assert!(matches(r"(cat) and \1", "cat and cat"));
+        assert!(!matches(r"(cat) and \1", "cat and dog"));
+    }
+
+    #[test]
+    fn character_class_ranges_combine_in_one_group() {
+        assert!(matches("[A-Fa-f0-9]+", "Ff0"));
+        assert!(matches("[A-Fa-f0-9]+", "deadBEEF"));
+        assert!(!matches("[A-Fa-f0-9]+", "ghi"));
+    }
+
+    #[test]
+    fn multi_byte_chars_are_indexed_by_char_not_byte() {
+        // "café" has a 2-byte 'é'; a byte-indexed matcher would misalign the
+        // anchors and the literal that follows it.
+        assert!(matches("café$", "café"));
+        assert!(matches("^café", "café au lait"));
+        assert!(matches(".", "é"));
+    }
+
+    #[test]
+    fn anchor_composes_with_a_following_quantifier() {
+        // `^a+b` must let the `a+` repeat past the first `a`, not collapse
+        // to matching a single anchored `a` followed by a loose `+b`.
+        assert!(matches("^a+b", "aaab"));
+        assert!(!matches("^a+b", "baaab"));
+    }
+
+    #[test]
+    fn anchors_compose_with_groups_and_alternation() {
+        // `^`/`$` must be ordinary fragments in the sequence, not wrappers
+        // around the group/alternation next to them, or these all panic.
+        assert!(matches("^(cat|dog)", "cat sat"));
+        assert!(!matches("^(cat|dog)", "a cat sat"));
+        assert!(matches("(cat)$", "a cat"));
+        assert!(!matches("(cat)$", "a cat sat"));
+        assert!(matches("^(a|b)+c$", "abaac"));
+    }
 
-    if match_pattern(&input_line, &pattern) {
-        process::exit(0)
-    } else {
-        process::exit(1)
+    #[test]
+    fn engine_can_be_observed_and_overridden() {
+        // Backreferences always force backtracking...
+        let backreference = Matcher::from_pattern(r"(a)\1", false, false);
+        assert_eq!(backreference.engine(), Engine::Backtracking);
+
+        // ...while an unbounded quantifier with no backreference defaults to
+        // the NFA, and can still be forced back to backtracking explicitly.
+        let unbounded = Matcher::from_pattern("a+b", false, false);
+        assert_eq!(unbounded.engine(), Engine::Nfa);
+        let forced = unbounded.with_engine(Engine::Backtracking);
+        assert_eq!(forced.engine(), Engine::Backtracking);
+        assert!(forced.r#match("aaab"));
     }
 }
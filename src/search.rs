@@ -0,0 +1,155 @@
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+/// Flags controlling how `run` walks its sources and what it prints, modeled
+/// on the subset of ripgrep's line-searcher flags this tool supports.
+#[derive(Debug, Default)]
+pub struct Options {
+    pub line_numbers: bool,
+    pub invert: bool,
+    pub count_only: bool,
+    pub list_files: bool,
+    pub recursive: bool,
+    pub case_insensitive: bool,
+    pub smart_case: bool,
+}
+
+/// Search `paths` (or stdin, if empty) for lines matching `pattern`, printing
+/// results per `options`. Returns whether at least one line was selected (a
+/// matching line, or with `invert` a non-matching one), which is what the
+/// caller uses to pick an exit code.
+///
+/// The pattern is parsed into a `Matcher` once up front and reused for every
+/// line, rather than re-parsing and re-compiling it per line.
+pub fn run(pattern: &str, paths: &[String], options: &Options) -> io::Result<bool> {
+    let matcher = crate::Matcher::from_pattern(pattern, options.case_insensitive, options.smart_case);
+
+    if paths.is_empty() {
+        return search_stdin(&matcher, options);
+    }
+
+    let files = collect_files(paths, options.recursive)?;
+    let show_filename = files.len() > 1;
+    let mut any_match = false;
+
+    for file in &files {
+        if search_file(&matcher, file, show_filename, options)? {
+            any_match = true;
+        }
+    }
+
+    Ok(any_match)
+}
+
+fn search_stdin(matcher: &crate::Matcher, options: &Options) -> io::Result<bool> {
+    let stdin = io::stdin();
+    let mut match_count = 0;
+
+    for (index, line) in stdin.lock().lines().enumerate() {
+        let line = line?;
+        if matcher.r#match(&line) != options.invert {
+            match_count += 1;
+            if !options.count_only && !options.list_files {
+                print_line(None, index + 1, options.line_numbers, &line);
+            }
+        }
+    }
+
+    if options.count_only {
+        println!("{match_count}");
+    }
+
+    Ok(match_count > 0)
+}
+
+fn search_file(
+    matcher: &crate::Matcher,
+    file: &Path,
+    show_filename: bool,
+    options: &Options,
+) -> io::Result<bool> {
+    let content = fs::read_to_string(file)?;
+    let mut match_count = 0;
+
+    for (index, line) in content.lines().enumerate() {
+        if matcher.r#match(line) != options.invert {
+            match_count += 1;
+            if !options.count_only && !options.list_files {
+                let file = show_filename.then_some(file);
+                print_line(file, index + 1, options.line_numbers, line);
+            }
+        }
+    }
+
+    if options.list_files {
+        if match_count > 0 {
+            println!("{}", file.display());
+        }
+    } else if options.count_only {
+        if show_filename {
+            println!("{}:{}", file.display(), match_count);
+        } else {
+            println!("{}", match_count);
+        }
+    }
+
+    Ok(match_count > 0)
+}
+
+fn print_line(file: Option<&Path>, line_number: usize, show_line_number: bool, line: &str) {
+    let mut prefix = String::new();
+
+    if let Some(file) = file {
+        prefix.push_str(&file.display().to_string());
+        prefix.push(':');
+    }
+
+    if show_line_number {
+        prefix.push_str(&line_number.to_string());
+        prefix.push(':');
+    }
+
+    println!("{prefix}{line}");
+}
+
+/// Expand `paths` into a flat list of files in argument order, with each
+/// directory's contents sorted by path, recursing into
+/// directories only when `recursive` is set (otherwise they're reported and
+/// skipped, matching grep's behavior without `-r`).
+fn collect_files(paths: &[String], recursive: bool) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    for path in paths {
+        let path = Path::new(path);
+
+        if path.is_dir() {
+            if recursive {
+                collect_dir(path, &mut files)?;
+            } else {
+                eprintln!("{}: Is a directory", path.display());
+            }
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+
+    Ok(files)
+}
+
+fn collect_dir(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries: Vec<PathBuf> = fs::read_dir(dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    entries.sort();
+
+    for path in entries {
+        if path.is_dir() {
+            collect_dir(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+
+    Ok(())
+}